@@ -2,6 +2,9 @@ use parking_lot::{Mutex};
 use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
 use std::{ffi::CStr, os::raw::c_char, u32};
 
+mod mp4;
+pub use mp4::bpm_emsg;
+
 // Maximum number of video encoder renditions
 const MAX_OUTPUT_VIDEO_ENCODERS: usize = 6;
 
@@ -13,6 +16,26 @@ enum _bpm_sei_types {
 	BPM_MAX_SEI
 }
 
+// Video codec carrying the BPM payload, used to pick the right SEI/OBU framing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    H264,
+    H265,
+    AV1,
+}
+
+impl Codec {
+    /// Derive the codec from a track fingerprint of the form `codec_WxH_fps`
+    /// (e.g. `h265_1920x1080_60`), as passed to `get_track_index`.
+    fn from_fingerprint(fingerprint: &str) -> Codec {
+        match fingerprint.split('_').next().unwrap_or("").to_ascii_lowercase().as_str() {
+            "h265" | "hevc" => Codec::H265,
+            "av1" => Codec::AV1,
+            _ => Codec::H264,
+        }
+    }
+}
+
 const SEI_UUID_SIZE: usize = 16;
 const UUID_TS: [u8; SEI_UUID_SIZE] = [ 0x0a, 0xec, 0xff, 0xe7, 0x52, 0x72, 0x4e, 0x2f, 0xa6, 0x2f, 0xd1, 0x9c, 0xd6, 0x1a, 0x93, 0xb5 ];
 const UUID_SM: [u8; SEI_UUID_SIZE] = [ 0xca, 0x60, 0xe7, 0x1c, 0x6a, 0x8b, 0x43, 0x88, 0xa3, 0x77, 0x15, 0x1d, 0xf7, 0xbf, 0x8a, 0xc2 ];
@@ -60,6 +83,7 @@ const BPM_TS_EVENT_PIR: u8 = 4;   // Packet Interleave Request Event
 
 struct State {
     track_map: Vec<String>, // Track fingerprints for index in the metrics arrays
+    track_codecs: Vec<Codec>, // Codec derived from each track's fingerprint, same indexing as track_map
 
     // Session metrics
     sm_rendered: u32, // Frames rendered by compositor
@@ -71,12 +95,24 @@ struct State {
     erm_input: Vec<u32>,   // Frames input to the encoder rendition
     erm_skipped: Vec<u32>, // Frames skipped by the encoder rendition
     erm_output: Vec<u32>,  // Frames output (encoded) by the encoder rendition
+
+    // Session metrics snapshot as of the last bpm_sm() call, used to compute deltas
+    last_sm_rendered: u32,
+    last_sm_lagged: u32,
+    last_sm_dropped: u32,
+    last_sm_output: u32,
+
+    // Encoded Rendition Metrics snapshot as of the last bpm_erm() call, used to compute deltas
+    last_erm_input: Vec<u32>,
+    last_erm_skipped: Vec<u32>,
+    last_erm_output: Vec<u32>,
 }
 
 impl Default for State {
     fn default() -> State {
         State {
             track_map: Vec::new(),
+            track_codecs: Vec::new(),
             sm_rendered: 0,
             sm_lagged: 0,
             sm_dropped: 0,
@@ -84,6 +120,13 @@ impl Default for State {
             erm_input: vec![0; MAX_OUTPUT_VIDEO_ENCODERS],
             erm_skipped: vec![0; MAX_OUTPUT_VIDEO_ENCODERS],
             erm_output: vec![0; MAX_OUTPUT_VIDEO_ENCODERS],
+            last_sm_rendered: 0,
+            last_sm_lagged: 0,
+            last_sm_dropped: 0,
+            last_sm_output: 0,
+            last_erm_input: vec![0; MAX_OUTPUT_VIDEO_ENCODERS],
+            last_erm_skipped: vec![0; MAX_OUTPUT_VIDEO_ENCODERS],
+            last_erm_output: vec![0; MAX_OUTPUT_VIDEO_ENCODERS],
         }
     }
 }
@@ -96,34 +139,53 @@ impl State {
             if self.track_map.len() >= MAX_OUTPUT_VIDEO_ENCODERS {
                 panic!("Exceeded MAX_OUTPUT_VIDEO_ENCODERS limit");
             }
+            self.track_codecs.push(Codec::from_fingerprint(&fingerprint));
             self.track_map.push(fingerprint);
             self.track_map.len() - 1
         }
     }
 }
 
-// global state
+// global state, used by the bpm_* convenience functions below
 lazy_static::lazy_static! {
     static ref STATE: Mutex<State> = Mutex::new(State::default());
 }
 
+/// Opaque per-session BPM context. Lets a host (e.g. an encoder farm) run several
+/// simultaneous outputs, each with its own track_map and counter arrays, instead of
+/// serializing every FFI call on one global mutex. Mirrors the direction the fMP4
+/// muxer took when it generalized from a single stream to N streams.
+pub struct BpmSession {
+    state: Mutex<State>,
+}
 
-/// Get the index for the track by track fingerprint (e.g. codec_resolution_fps).
-/// Used if the track index is not known by the encoder.
+/// Create a new, independent BPM session. Must be freed with bpm_session_destroy.
 #[no_mangle]
-pub extern "C" fn bpm_get_track_index(track_fp: *const c_char) -> i32 {
+pub extern "C" fn bpm_session_create() -> *mut BpmSession {
+    Box::into_raw(Box::new(BpmSession { state: Mutex::new(State::default()) }))
+}
+
+/// Destroy a session created by bpm_session_create.
+#[no_mangle]
+pub extern "C" fn bpm_session_destroy(session: *mut BpmSession) {
+    if !session.is_null() {
+        unsafe {
+            let _ = Box::from_raw(session);
+        }
+    }
+}
+
+fn get_track_index_locked(state: &Mutex<State>, track_fp: *const c_char) -> i32 {
     if let Some(track_fp_str) = c_char_to_string(track_fp) {
-        let mut state = STATE.lock();
+        let mut state = state.lock();
         let track_idx = state.get_track_index(track_fp_str);
         return track_idx as i32;
     }
     return -1;
 }
 
-/// Frame encoded successfully
-#[no_mangle]
-pub extern "C" fn bpm_frame_encoded(track_idx: u32) {
-    let mut state = STATE.lock();
+fn frame_encoded_locked(state: &Mutex<State>, track_idx: u32) {
+    let mut state = state.lock();
 
     // Spec: "The primary, highest quality video track must be packaged
     // and sent as enhanced RTMP single-track video packets" = track 0
@@ -139,10 +201,8 @@ pub extern "C" fn bpm_frame_encoded(track_idx: u32) {
     state.erm_output[track_idx as usize] += 1;
 }
 
-/// Frame lagged while encoding
-#[no_mangle]
-pub extern "C" fn bpm_frame_lagged(track_idx: u32) {
-    let mut state = STATE.lock();
+fn frame_lagged_locked(state: &Mutex<State>, track_idx: u32) {
+    let mut state = state.lock();
     state.sm_lagged += 1;
 
     // Frames input to the encoder rendition
@@ -152,10 +212,8 @@ pub extern "C" fn bpm_frame_lagged(track_idx: u32) {
     state.erm_skipped[track_idx as usize] += 1;
 }
 
-/// Frame dropped due to network congestion
-#[no_mangle]
-pub extern "C" fn bpm_frame_dropped(track_idx: u32) {
-    let mut state = STATE.lock();
+fn frame_dropped_locked(state: &Mutex<State>, track_idx: u32) {
+    let mut state = state.lock();
     state.sm_dropped += 1;
 
     // Frames input to the encoder rendition
@@ -165,93 +223,447 @@ pub extern "C" fn bpm_frame_dropped(track_idx: u32) {
     state.erm_skipped[track_idx as usize] += 1;
 }
 
-/// BPM Timestamp
-pub fn bpm_ts(ts_cts: i64, ts_fer: i64, ts_ferc: i64, ts_pir: i64) -> [u8; 125] {
-    let now = now_in_rfc3339();
-    let cts = if ts_cts > 0 { millis_in_rfc3339(ts_cts) } else { now.clone() };
-    let fer = if ts_fer > 0 { millis_in_rfc3339(ts_fer) } else { now.clone() };
-    let ferc = if ts_ferc > 0 { millis_in_rfc3339(ts_ferc) } else { now.clone() };
-    let pir = if ts_pir > 0 { millis_in_rfc3339(ts_pir) } else { now.clone() };
+/// Get the index for the track by track fingerprint (e.g. codec_resolution_fps).
+/// Used if the track index is not known by the encoder.
+#[no_mangle]
+pub extern "C" fn bpm_get_track_index(track_fp: *const c_char) -> i32 {
+    get_track_index_locked(&STATE, track_fp)
+}
+
+/// Frame encoded successfully
+#[no_mangle]
+pub extern "C" fn bpm_frame_encoded(track_idx: u32) {
+    frame_encoded_locked(&STATE, track_idx);
+}
+
+/// Frame lagged while encoding
+#[no_mangle]
+pub extern "C" fn bpm_frame_lagged(track_idx: u32) {
+    frame_lagged_locked(&STATE, track_idx);
+}
+
+/// Frame dropped due to network congestion
+#[no_mangle]
+pub extern "C" fn bpm_frame_dropped(track_idx: u32) {
+    frame_dropped_locked(&STATE, track_idx);
+}
+
+/// Get the index for the track by track fingerprint, scoped to `session`.
+#[no_mangle]
+pub extern "C" fn bpm_session_get_track_index(session: *mut BpmSession, track_fp: *const c_char) -> i32 {
+    if session.is_null() {
+        return -1;
+    }
+    get_track_index_locked(&unsafe { &*session }.state, track_fp)
+}
+
+/// Frame encoded successfully, scoped to `session`.
+#[no_mangle]
+pub extern "C" fn bpm_session_frame_encoded(session: *mut BpmSession, track_idx: u32) {
+    if session.is_null() {
+        return;
+    }
+    frame_encoded_locked(&unsafe { &*session }.state, track_idx);
+}
+
+/// Frame lagged while encoding, scoped to `session`.
+#[no_mangle]
+pub extern "C" fn bpm_session_frame_lagged(session: *mut BpmSession, track_idx: u32) {
+    if session.is_null() {
+        return;
+    }
+    frame_lagged_locked(&unsafe { &*session }.state, track_idx);
+}
+
+/// Frame dropped due to network congestion, scoped to `session`.
+#[no_mangle]
+pub extern "C" fn bpm_session_frame_dropped(session: *mut BpmSession, track_idx: u32) {
+    if session.is_null() {
+        return;
+    }
+    frame_dropped_locked(&unsafe { &*session }.state, track_idx);
+}
 
-    let mut ts_data: [u8; 125] = [0; 125];
-    ts_data[0..16].copy_from_slice(&UUID_TS);
-    ts_data[16] = 0x03;                                     // ts_reserved_zero_4bits & num_timestamps_minus1
+/// Annex-B start code, prepended to every wrapped SEI NAL unit.
+const ANNEXB_START_CODE: [u8; 4] = [0x00, 0x00, 0x00, 0x01];
 
-    ts_data[17] = TS_TYPE;
-    ts_data[18] = BPM_TS_EVENT_CTS;                         // Composition Time Event
-    ts_data[19..43].copy_from_slice(cts.as_bytes());
-    ts_data[43] = NULL;
+/// H.264 NAL unit header for a SEI NAL: forbidden_zero_bit=0, nal_ref_idc=0, nal_unit_type=6.
+const NAL_HEADER_SEI_H264: u8 = 0x06;
 
-    ts_data[44] = TS_TYPE;
-    ts_data[45] = BPM_TS_EVENT_FER;                         // Frame Encode Request Event
-    ts_data[46..70].copy_from_slice(fer.as_bytes());
-    ts_data[70] = NULL;
+/// SEI payloadType for user_data_unregistered.
+const SEI_PAYLOAD_TYPE_USER_DATA_UNREGISTERED: u8 = 5;
 
-    ts_data[71] = TS_TYPE;
-    ts_data[72] = BPM_TS_EVENT_FERC;                        // Frame Encode Request Complete
-    ts_data[73..97].copy_from_slice(ferc.as_bytes());
-    ts_data[97] = NULL;
+/// rbsp_trailing_bits: stop bit followed by zero padding.
+const RBSP_TRAILING_BITS: u8 = 0x80;
 
-    ts_data[98] = TS_TYPE;
-    ts_data[99] = BPM_TS_EVENT_PIR;                         // Packet Interleave Request Event
-    ts_data[100..124].copy_from_slice(pir.as_bytes());
-    ts_data[124] = NULL;
+/// Wrap a BPM payload (UUID + timestamps/counters) as a spec-valid H.264
+/// `user_data_unregistered` SEI NAL unit: NAL header, SEI message header
+/// (payloadType/payloadSize), the payload itself, and rbsp_trailing_bits, with
+/// emulation-prevention byte insertion applied across the whole RBSP so the result
+/// can be spliced directly into an Annex-B bitstream.
+pub fn wrap_sei_h264(payload: &[u8]) -> Vec<u8> {
+    let rbsp = build_sei_rbsp(payload);
 
-    return ts_data;
+    let mut nal = Vec::with_capacity(ANNEXB_START_CODE.len() + 1 + rbsp.len() + rbsp.len() / 3);
+    nal.extend_from_slice(&ANNEXB_START_CODE);
+    nal.push(NAL_HEADER_SEI_H264);
+    escape_emulation_prevention(&rbsp, &mut nal);
+    nal
 }
 
-/// BPM Session Metrics
-pub fn bpm_sm() -> [u8; 66] {
-    let state = STATE.lock();
+/// Build the SEI message RBSP (header + payload + trailing bits), excluding the NAL
+/// unit header, since emulation prevention does not apply to the header byte(s).
+fn build_sei_rbsp(payload: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(payload.len() + 8);
+    push_sei_size(&mut rbsp, SEI_PAYLOAD_TYPE_USER_DATA_UNREGISTERED as usize);
+    push_sei_size(&mut rbsp, payload.len());
+    rbsp.extend_from_slice(payload);
+    rbsp.push(RBSP_TRAILING_BITS);
+    rbsp
+}
+
+/// Encode a SEI payloadType/payloadSize field: a run of 0xFF for every full 255,
+/// followed by the remainder byte.
+fn push_sei_size(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 255 {
+        out.push(0xFF);
+        value -= 255;
+    }
+    out.push(value as u8);
+}
+
+/// Append `rbsp` to `out`, inserting an emulation_prevention_three_byte (0x03)
+/// whenever the next three bytes would otherwise form 00 00 00, 00 00 01, 00 00 02,
+/// or 00 00 03.
+fn escape_emulation_prevention(rbsp: &[u8], out: &mut Vec<u8>) {
+    let mut zero_run = 0;
+    for &byte in rbsp {
+        if zero_run >= 2 && byte <= 0x03 {
+            out.push(0x03);
+            zero_run = 0;
+        }
+        out.push(byte);
+        if byte == 0x00 {
+            zero_run += 1;
+        } else {
+            zero_run = 0;
+        }
+    }
+}
+
+/// H.265 NAL unit header for a prefix SEI NAL: nal_unit_type=39 (PREFIX_SEI_NUT),
+/// layer_id=0, temporal_id_plus1=1.
+const NAL_HEADER_SEI_H265: [u8; 2] = [0x4E, 0x01];
+
+/// Wrap a BPM payload as a spec-valid H.265 prefix `user_data_unregistered` SEI NAL
+/// unit. The SEI message body and emulation-prevention rules are identical to H.264;
+/// only the (2-byte) NAL unit header differs.
+pub fn wrap_sei_h265(payload: &[u8]) -> Vec<u8> {
+    let rbsp = build_sei_rbsp(payload);
+
+    let mut nal = Vec::with_capacity(ANNEXB_START_CODE.len() + 2 + rbsp.len() + rbsp.len() / 3);
+    nal.extend_from_slice(&ANNEXB_START_CODE);
+    nal.extend_from_slice(&NAL_HEADER_SEI_H265);
+    escape_emulation_prevention(&rbsp, &mut nal);
+    nal
+}
+
+/// AV1 OBU header for a metadata OBU with the size field present:
+/// obu_forbidden_bit=0, obu_type=6 (OBU_METADATA), obu_extension_flag=0,
+/// obu_has_size_field=1, obu_reserved_1bit=0.
+const OBU_HEADER_METADATA_HAS_SIZE: u8 = 0x32;
+
+/// AV1 metadata_type for ITU-T T.35 metadata.
+const AV1_METADATA_TYPE_ITUT_T35: u8 = 4;
+
+/// ITU-T T.35 country code for the United States, used as a generic carrier for
+/// non-standardized (BPM) payloads, matching how closed-caption SEI payloads are framed.
+const ITU_T_T35_COUNTRY_CODE_UNITED_STATES: u8 = 0xB5;
+
+/// Wrap a BPM payload as an AV1 metadata OBU (`obu_type = 6`), using
+/// `METADATA_TYPE_ITUT_T35`-style framing and leb128-encoded sizes.
+pub fn wrap_metadata_obu_av1(payload: &[u8]) -> Vec<u8> {
+    let mut metadata = Vec::with_capacity(payload.len() + 2);
+    write_leb128(&mut metadata, AV1_METADATA_TYPE_ITUT_T35 as u64);
+    metadata.push(ITU_T_T35_COUNTRY_CODE_UNITED_STATES);
+    metadata.extend_from_slice(payload);
+
+    let mut obu = Vec::with_capacity(metadata.len() + 2);
+    obu.push(OBU_HEADER_METADATA_HAS_SIZE);
+    write_leb128(&mut obu, metadata.len() as u64);
+    obu.extend_from_slice(&metadata);
+    obu
+}
+
+/// Encode `value` as AV1 leb128 (little-endian base-128, 7 bits per byte, high bit
+/// set on every byte but the last).
+fn write_leb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let mut byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value != 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if value == 0 {
+            break;
+        }
+    }
+}
+
+/// Wrap a BPM payload for the given codec's SEI/OBU framing.
+pub fn wrap_sei(payload: &[u8], codec: Codec) -> Vec<u8> {
+    match codec {
+        Codec::H264 => wrap_sei_h264(payload),
+        Codec::H265 => wrap_sei_h265(payload),
+        Codec::AV1 => wrap_metadata_obu_av1(payload),
+    }
+}
+
+/// BPM Timestamp, wrapped for the given codec's SEI/OBU framing.
+pub fn bpm_ts_for_codec(ts_cts: i64, ts_fer: i64, ts_ferc: i64, ts_pir: i64, codec: Codec) -> Vec<u8> {
+    wrap_sei(&bpm_ts(ts_cts, ts_fer, ts_ferc, ts_pir), codec)
+}
+
+/// BPM Session Metrics, wrapped for the given codec's SEI/OBU framing.
+pub fn bpm_sm_for_codec(codec: Codec) -> Vec<u8> {
+    wrap_sei(&bpm_sm(), codec)
+}
+
+/// BPM Encoded Rendition Metrics, wrapped for the given codec's SEI/OBU framing.
+pub fn bpm_erm_for_codec(track_idx: u32, codec: Codec) -> Vec<u8> {
+    wrap_sei(&bpm_erm(track_idx), codec)
+}
+
+/// Codec recorded for `track_idx` by get_track_index, derived from its fingerprint.
+fn track_codec(track_idx: u32) -> Codec {
+    // track_codecs only grows via bpm_get_track_index, but (like erm_input/erm_output)
+    // a caller may address any track_idx < MAX_OUTPUT_VIDEO_ENCODERS without having
+    // registered it first, so fall back instead of indexing out of bounds.
+    STATE.lock().track_codecs.get(track_idx as usize).copied().unwrap_or(Codec::H264)
+}
+
+/// BPM Timestamp, wrapped using the codec recorded for `track_idx`.
+pub fn bpm_ts_for_track(track_idx: u32, ts_cts: i64, ts_fer: i64, ts_ferc: i64, ts_pir: i64) -> Vec<u8> {
+    bpm_ts_for_codec(ts_cts, ts_fer, ts_ferc, ts_pir, track_codec(track_idx))
+}
+
+/// BPM Session Metrics, wrapped using the codec recorded for `track_idx`.
+pub fn bpm_sm_for_track(track_idx: u32) -> Vec<u8> {
+    bpm_sm_for_codec(track_codec(track_idx))
+}
+
+/// BPM Encoded Rendition Metrics, wrapped using the codec recorded for `track_idx`.
+pub fn bpm_erm_for_track(track_idx: u32) -> Vec<u8> {
+    bpm_erm_for_codec(track_idx, track_codec(track_idx))
+}
+
+/// Serializes a BPM SEI payload (UUID, then a variable number of timestamp and
+/// counter fields) by appending fields as they're known and back-patching the
+/// `num_timestamps_minus1`/`num_counters_minus1` nibbles once the counts are final.
+/// This replaces the hardcoded byte offsets that `bpm_ts`/`bpm_sm`/`bpm_erm` used
+/// to write directly, so renditions can send any subset of timestamps/counters
+/// without the layout being fragile.
+struct SeiBuilder {
+    data: Vec<u8>,
+    timestamps_offset: usize,
+    num_timestamps: u8,
+    counters_offset: Option<usize>,
+    num_counters: u8,
+}
+
+impl SeiBuilder {
+    fn new(uuid: &[u8; SEI_UUID_SIZE]) -> SeiBuilder {
+        let mut data = Vec::new();
+        data.extend_from_slice(uuid);
+        let timestamps_offset = data.len();
+        data.push(0x00); // patched below with ts_reserved_zero_4bits & num_timestamps_minus1
+
+        SeiBuilder {
+            data,
+            timestamps_offset,
+            num_timestamps: 0,
+            counters_offset: None,
+            num_counters: 0,
+        }
+    }
+
+    /// Append one timestamp field: ts_type, event_tag, value, NULL terminator.
+    fn push_timestamp(&mut self, event_tag: u8, ts_type: u8, value: &[u8]) -> &mut Self {
+        self.data.push(ts_type);
+        self.data.push(event_tag);
+        self.data.extend_from_slice(value);
+        self.data.push(NULL);
+        self.num_timestamps += 1;
+        self
+    }
+
+    /// Append one counter field: counter_tag, 32-bit big-endian value. Opens the
+    /// counters section (and its num_counters_minus1 byte) on first use, so a
+    /// payload with no counters (e.g. BPM Timestamp) omits the section entirely.
+    fn push_counter(&mut self, tag: u8, value: u32) -> &mut Self {
+        if self.counters_offset.is_none() {
+            self.counters_offset = Some(self.data.len());
+            self.data.push(0x00); // patched below with ts_reserved_zero_4bits & num_counters_minus1
+        }
+        self.data.push(tag);
+        self.data.extend_from_slice(&value.to_be_bytes());
+        self.num_counters += 1;
+        self
+    }
+
+    /// Back-patch the num_timestamps_minus1/num_counters_minus1 nibbles and return
+    /// the finished payload.
+    fn build(&mut self) -> Vec<u8> {
+        self.data[self.timestamps_offset] = self.num_timestamps.saturating_sub(1);
+        if let Some(offset) = self.counters_offset {
+            self.data[offset] = self.num_counters.saturating_sub(1);
+        }
+        std::mem::take(&mut self.data)
+    }
+}
+
+/// BPM Timestamp
+pub fn bpm_ts(ts_cts: i64, ts_fer: i64, ts_ferc: i64, ts_pir: i64) -> Vec<u8> {
     let now = now_in_rfc3339();
+    let cts = if ts_cts > 0 { millis_in_rfc3339(ts_cts) } else { now.clone() };
+    let fer = if ts_fer > 0 { millis_in_rfc3339(ts_fer) } else { now.clone() };
+    let ferc = if ts_ferc > 0 { millis_in_rfc3339(ts_ferc) } else { now.clone() };
+    let pir = if ts_pir > 0 { millis_in_rfc3339(ts_pir) } else { now };
+
+    SeiBuilder::new(&UUID_TS)
+        .push_timestamp(BPM_TS_EVENT_CTS, TS_TYPE, cts.as_bytes())   // Composition Time Event
+        .push_timestamp(BPM_TS_EVENT_FER, TS_TYPE, fer.as_bytes())   // Frame Encode Request Event
+        .push_timestamp(BPM_TS_EVENT_FERC, TS_TYPE, ferc.as_bytes()) // Frame Encode Request Complete
+        .push_timestamp(BPM_TS_EVENT_PIR, TS_TYPE, pir.as_bytes())   // Packet Interleave Request Event
+        .build()
+}
+
+/// Wire encoding for a single BPM timestamp field.
+#[derive(Debug, Clone, Copy)]
+pub enum TsFormat {
+    Rfc3339,       // RFC3339 timestamp string (the original, variable-width encoding)
+    DurationMillis, // BPM_TS_DURATION: 64-bit big-endian milliseconds since epoch
+    DeltaNanos,     // BPM_TS_DELTA: 64-bit big-endian delta in nanoseconds
+}
 
-    let mut sm_data: [u8; 66] = [0; 66];
-    sm_data[0..16].copy_from_slice(&UUID_SM);
-    sm_data[16] = 0x00;                                     // ts_reserved_zero_4bits & num_timestamps_minus1
+/// A timestamp value paired with the wire encoding to write it with.
+#[derive(Debug, Clone, Copy)]
+pub struct TsField {
+    pub value: i64,
+    pub format: TsFormat,
+}
 
-    sm_data[17] = TS_TYPE;
-    sm_data[18] = BPM_TS_EVENT_PIR;                         // "Amazon IVS expects BPM SM SEI using timestamp_event only set to 4 (BPM_TS_EVENT_PIR)"
-    sm_data[19..43].copy_from_slice(now.as_bytes());
-    sm_data[43] = NULL;
+impl TsField {
+    pub fn rfc3339(value: i64) -> TsField {
+        TsField { value, format: TsFormat::Rfc3339 }
+    }
 
-    sm_data[44] = 0x03;                                     // ts_reserved_zero_4bits & num_counters_minus1
+    pub fn duration_millis(value: i64) -> TsField {
+        TsField { value, format: TsFormat::DurationMillis }
+    }
 
-    sm_data[45] = BpmSmType::BPM_SM_FRAMES_RENDERED as u8;
-    sm_data[46..50].copy_from_slice(&state.sm_rendered.to_be_bytes()); // FIXME The 32-bit difference value for the specified counter_tag, relative to the last time it was sent. For example, with 60 fps rendering, each 2 seconds counter_value should be 120.
-    sm_data[50] = BpmSmType::BPM_SM_FRAMES_LAGGED as u8;
-    sm_data[51..55].copy_from_slice(&state.sm_lagged.to_be_bytes()); // FIXME
-    sm_data[55] = BpmSmType::BPM_SM_FRAMES_DROPPED as u8;
-    sm_data[56..60].copy_from_slice(&state.sm_dropped.to_be_bytes()); // FIXME
-    sm_data[61] = BpmSmType::BPM_SM_FRAMES_OUTPUT as u8;
-    sm_data[62..66].copy_from_slice(&state.sm_output.to_be_bytes()); // FIXME
+    pub fn delta_nanos(value: i64) -> TsField {
+        TsField { value, format: TsFormat::DeltaNanos }
+    }
+}
 
-    return sm_data;
+/// BPM Timestamp, with the wire encoding selectable per timestamp (RFC3339 string,
+/// BPM_TS_DURATION millis-since-epoch, or BPM_TS_DELTA nanosecond delta). The fixed
+/// 8-byte DURATION/DELTA fields are smaller on the wire than RFC3339 and, for DELTA,
+/// avoid a now_in_rfc3339() allocation per frame.
+pub fn bpm_ts_with_format(cts: TsField, fer: TsField, ferc: TsField, pir: TsField) -> Vec<u8> {
+    let mut builder = SeiBuilder::new(&UUID_TS);
+    push_ts_field(&mut builder, BPM_TS_EVENT_CTS, cts);   // Composition Time Event
+    push_ts_field(&mut builder, BPM_TS_EVENT_FER, fer);   // Frame Encode Request Event
+    push_ts_field(&mut builder, BPM_TS_EVENT_FERC, ferc); // Frame Encode Request Complete
+    push_ts_field(&mut builder, BPM_TS_EVENT_PIR, pir);   // Packet Interleave Request Event
+    builder.build()
 }
 
-/// BPM Encoded Rendition Metrics
-pub fn bpm_erm(track_idx: u32) -> [u8; 60] {
-    let state = STATE.lock();
+fn push_ts_field(builder: &mut SeiBuilder, event_tag: u8, field: TsField) {
+    match field.format {
+        TsFormat::Rfc3339 => {
+            let ts = if field.value > 0 { millis_in_rfc3339(field.value) } else { now_in_rfc3339() };
+            builder.push_timestamp(event_tag, _bpm_ts_type::BPM_TS_RFC3339 as u8, ts.as_bytes());
+        }
+        TsFormat::DurationMillis => {
+            let millis = if field.value > 0 { field.value } else { Utc::now().timestamp_millis() };
+            builder.push_timestamp(event_tag, _bpm_ts_type::BPM_TS_DURATION as u8, &(millis as u64).to_be_bytes());
+        }
+        TsFormat::DeltaNanos => {
+            builder.push_timestamp(event_tag, _bpm_ts_type::BPM_TS_DELTA as u8, &(field.value as u64).to_be_bytes());
+        }
+    }
+}
+
+fn bpm_sm_locked(state: &Mutex<State>) -> Vec<u8> {
+    let mut state = state.lock();
     let now = now_in_rfc3339();
 
-    let mut erm_data: [u8; 60] = [0; 60];
-    erm_data[0..16].copy_from_slice(&UUID_ERM);
-    erm_data[16] = 0x00;                                     // ts_reserved_zero_4bits & num_timestamps_minus1
+    // Per spec, counter_value is the 32-bit difference since the last time that
+    // counter was sent (e.g. ~120 for 60fps rendering over 2s), not a cumulative
+    // total. wrapping_sub handles a counter that restarted from zero.
+    let rendered_delta = state.sm_rendered.wrapping_sub(state.last_sm_rendered);
+    let lagged_delta = state.sm_lagged.wrapping_sub(state.last_sm_lagged);
+    let dropped_delta = state.sm_dropped.wrapping_sub(state.last_sm_dropped);
+    let output_delta = state.sm_output.wrapping_sub(state.last_sm_output);
+
+    state.last_sm_rendered = state.sm_rendered;
+    state.last_sm_lagged = state.sm_lagged;
+    state.last_sm_dropped = state.sm_dropped;
+    state.last_sm_output = state.sm_output;
+
+    SeiBuilder::new(&UUID_SM)
+        .push_timestamp(BPM_TS_EVENT_PIR, TS_TYPE, now.as_bytes()) // "Amazon IVS expects BPM SM SEI using timestamp_event only set to 4 (BPM_TS_EVENT_PIR)"
+        .push_counter(BpmSmType::BPM_SM_FRAMES_RENDERED as u8, rendered_delta)
+        .push_counter(BpmSmType::BPM_SM_FRAMES_LAGGED as u8, lagged_delta)
+        .push_counter(BpmSmType::BPM_SM_FRAMES_DROPPED as u8, dropped_delta)
+        .push_counter(BpmSmType::BPM_SM_FRAMES_OUTPUT as u8, output_delta)
+        .build()
+}
+
+fn bpm_erm_locked(state: &Mutex<State>, track_idx: u32) -> Vec<u8> {
+    let mut state = state.lock();
+    let now = now_in_rfc3339();
+    let track_idx = track_idx as usize;
+
+    // Per spec, counter_value is the 32-bit difference since the last time that
+    // counter was sent; wrapping_sub handles a counter that restarted from zero.
+    let input_delta = state.erm_input[track_idx].wrapping_sub(state.last_erm_input[track_idx]);
+    let skipped_delta = state.erm_skipped[track_idx].wrapping_sub(state.last_erm_skipped[track_idx]);
+    let output_delta = state.erm_output[track_idx].wrapping_sub(state.last_erm_output[track_idx]);
+
+    state.last_erm_input[track_idx] = state.erm_input[track_idx];
+    state.last_erm_skipped[track_idx] = state.erm_skipped[track_idx];
+    state.last_erm_output[track_idx] = state.erm_output[track_idx];
+
+    SeiBuilder::new(&UUID_ERM)
+        .push_timestamp(BPM_TS_EVENT_PIR, TS_TYPE, now.as_bytes()) // "Amazon IVS expects BPM ERM SEI using timestamp_event set only to 4 (BPM_TS_EVENT_PIR)."
+        .push_counter(BpmErmType::BPM_ERM_FRAMES_INPUT as u8, input_delta)
+        .push_counter(BpmErmType::BPM_ERM_FRAMES_SKIPPED as u8, skipped_delta)
+        .push_counter(BpmErmType::BPM_ERM_FRAMES_OUTPUT as u8, output_delta)
+        .build()
+}
 
-    erm_data[17] = TS_TYPE;
-    erm_data[18] = BPM_TS_EVENT_PIR;                         // "Amazon IVS expects BPM ERM SEI using timestamp_event set only to 4 (BPM_TS_EVENT_PIR)."
-    erm_data[19..43].copy_from_slice(now.as_bytes());
-    erm_data[43] = NULL;
+/// BPM Session Metrics
+pub fn bpm_sm() -> Vec<u8> {
+    bpm_sm_locked(&STATE)
+}
 
-    erm_data[44] = 0x02;                                     // ts_reserved_zero_4bits & num_counters_minus1
+/// BPM Encoded Rendition Metrics
+pub fn bpm_erm(track_idx: u32) -> Vec<u8> {
+    bpm_erm_locked(&STATE, track_idx)
+}
 
-    erm_data[45] = BpmErmType::BPM_ERM_FRAMES_INPUT as u8;
-    erm_data[46..50].copy_from_slice(&state.erm_input[track_idx as usize].to_be_bytes()); // FIXME The 32-bit difference value for the specified counter_tag, relative to the last time it was sent. For example, with 60 fps rendering, each 2 seconds counter_value should be 120.
-    erm_data[50] = BpmErmType::BPM_ERM_FRAMES_SKIPPED as u8;
-    erm_data[51..55].copy_from_slice(&state.erm_skipped[track_idx as usize].to_be_bytes()); // FIXME
-    erm_data[55] = BpmErmType::BPM_ERM_FRAMES_OUTPUT as u8;
-    erm_data[56..60].copy_from_slice(&state.erm_output[track_idx as usize].to_be_bytes()); // FIXME
+/// BPM Session Metrics, scoped to `session`.
+pub fn bpm_session_sm(session: &BpmSession) -> Vec<u8> {
+    bpm_sm_locked(&session.state)
+}
 
-    return erm_data;
+/// BPM Encoded Rendition Metrics, scoped to `session`.
+pub fn bpm_session_erm(session: &BpmSession, track_idx: u32) -> Vec<u8> {
+    bpm_erm_locked(&session.state, track_idx)
 }
 
 /// Pointer to BPM Timestamp data.
@@ -262,12 +674,11 @@ pub extern "C" fn bpm_ts_ptr(ts_data: *mut *mut u8, ts_size: *mut u32) -> i32 {
         return -1;
     }
 
-    let ts = bpm_ts(0, 0, 0, 0);
+    let ts = bpm_ts(0, 0, 0, 0).into_boxed_slice();
     let size = ts.len();
-    let box_ptr = Box::new(ts);
 
     unsafe {
-        *ts_data = Box::into_raw(box_ptr) as *mut u8;
+        *ts_data = Box::into_raw(ts) as *mut u8;
         *ts_size = size as u32;
     }
 
@@ -282,12 +693,11 @@ pub extern "C" fn bpm_sm_ptr(ts_data: *mut *mut u8, ts_size: *mut u32) -> i32 {
         return -1;
     }
 
-    let ts = bpm_ts(0, 0, 0, 0);
-    let size = ts.len();
-    let box_ptr = Box::new(ts);
+    let sm = bpm_sm().into_boxed_slice();
+    let size = sm.len();
 
     unsafe {
-        *ts_data = Box::into_raw(box_ptr) as *mut u8;
+        *ts_data = Box::into_raw(sm) as *mut u8;
         *ts_size = size as u32;
     }
 
@@ -297,17 +707,54 @@ pub extern "C" fn bpm_sm_ptr(ts_data: *mut *mut u8, ts_size: *mut u32) -> i32 {
 /// Pointer to BPM Encoded Rendition Metrics data.
 /// Memory must be freed by the caller using bpm_destroy.
 #[no_mangle]
-pub extern "C" fn bpm_erm_ptr(ts_data: *mut *mut u8, ts_size: *mut u32) -> i32 {
+pub extern "C" fn bpm_erm_ptr(track_idx: u32, ts_data: *mut *mut u8, ts_size: *mut u32) -> i32 {
     if ts_data.is_null() || ts_size.is_null() {
         return -1;
     }
 
-    let ts = bpm_ts(0, 0, 0, 0);
-    let size = ts.len();
-    let box_ptr = Box::new(ts);
+    let erm = bpm_erm(track_idx).into_boxed_slice();
+    let size = erm.len();
+
+    unsafe {
+        *ts_data = Box::into_raw(erm) as *mut u8;
+        *ts_size = size as u32;
+    }
+
+    return 0;
+}
+
+/// Pointer to BPM Session Metrics data, scoped to `session`.
+/// Memory must be freed by the caller using bpm_destroy.
+#[no_mangle]
+pub extern "C" fn bpm_session_sm_ptr(session: *mut BpmSession, ts_data: *mut *mut u8, ts_size: *mut u32) -> i32 {
+    if session.is_null() || ts_data.is_null() || ts_size.is_null() {
+        return -1;
+    }
+
+    let sm = bpm_session_sm(unsafe { &*session }).into_boxed_slice();
+    let size = sm.len();
+
+    unsafe {
+        *ts_data = Box::into_raw(sm) as *mut u8;
+        *ts_size = size as u32;
+    }
+
+    return 0;
+}
+
+/// Pointer to BPM Encoded Rendition Metrics data, scoped to `session`.
+/// Memory must be freed by the caller using bpm_destroy.
+#[no_mangle]
+pub extern "C" fn bpm_session_erm_ptr(session: *mut BpmSession, track_idx: u32, ts_data: *mut *mut u8, ts_size: *mut u32) -> i32 {
+    if session.is_null() || ts_data.is_null() || ts_size.is_null() {
+        return -1;
+    }
+
+    let erm = bpm_session_erm(unsafe { &*session }, track_idx).into_boxed_slice();
+    let size = erm.len();
 
     unsafe {
-        *ts_data = Box::into_raw(box_ptr) as *mut u8;
+        *ts_data = Box::into_raw(erm) as *mut u8;
         *ts_size = size as u32;
     }
 
@@ -381,4 +828,57 @@ fn main() {
     // Add some test data
     bpm_frame_encoded(1);
     bpm_print_state();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_sei_size_encodes_a_run_of_full_255s() {
+        let mut out = Vec::new();
+        push_sei_size(&mut out, 260);
+        assert_eq!(out, vec![0xFF, 0x05]);
+    }
+
+    #[test]
+    fn escape_emulation_prevention_inserts_three_byte_after_00_00() {
+        let mut out = Vec::new();
+        escape_emulation_prevention(&[0x00, 0x00, 0x00], &mut out);
+        assert_eq!(out, vec![0x00, 0x00, 0x03, 0x00]);
+    }
+
+    #[test]
+    fn wrap_sei_h264_frames_and_escapes_the_payload() {
+        let payload = [0x00, 0x00, 0x00, 0x01, 0x02, 0x03, 0x00, 0x00, 0x00, 0xFF];
+        let nal = wrap_sei_h264(&payload);
+        assert_eq!(
+            nal,
+            vec![
+                0x00, 0x00, 0x00, 0x01, // Annex-B start code
+                0x06,                   // NAL header (SEI)
+                0x05,                   // payloadType = user_data_unregistered
+                0x0a,                   // payloadSize = 10
+                0x00, 0x00, 0x03, 0x00, 0x01, 0x02, 0x03, 0x00, 0x00, 0x03, 0x00, 0xff, // escaped payload
+                0x80,                   // rbsp_trailing_bits
+            ]
+        );
+    }
+
+    #[test]
+    fn bpm_sm_reports_the_delta_since_the_previous_call() {
+        // Establish a baseline so the assertion below doesn't depend on whatever
+        // earlier tests did to the shared global counters.
+        bpm_frame_encoded(0);
+        let _ = bpm_sm();
+
+        bpm_frame_encoded(0);
+        bpm_frame_encoded(0);
+        bpm_frame_encoded(0);
+        let sm = bpm_sm();
+
+        assert_eq!(sm[45], BpmSmType::BPM_SM_FRAMES_RENDERED as u8);
+        let rendered_delta = u32::from_be_bytes(sm[46..50].try_into().unwrap());
+        assert_eq!(rendered_delta, 3);
+    }
 }
\ No newline at end of file
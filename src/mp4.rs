@@ -0,0 +1,46 @@
+//! ISO-BMFF box writing for carrying BPM metrics as `emsg` event-message boxes in
+//! fragmented MP4 / CMAF, for packagers (DASH/HLS) that can't use inline SEI.
+//! No metadata `trak`/`moof` is written.
+
+/// Write a length-prefixed ISO-BMFF box: reserve 4 bytes for size, write the fourcc,
+/// write `contents`, then back-patch the big-endian size.
+pub(crate) fn write_box(fourcc: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(8 + contents.len());
+    data.extend_from_slice(&[0, 0, 0, 0]); // size, patched below
+    data.extend_from_slice(fourcc);
+    data.extend_from_slice(contents);
+
+    let size = data.len() as u32;
+    data[0..4].copy_from_slice(&size.to_be_bytes());
+    data
+}
+
+/// Write a "full box": `write_box` plus the version/flags word that precedes `contents`.
+pub(crate) fn write_full_box(fourcc: &[u8; 4], version: u8, flags: u32, contents: &[u8]) -> Vec<u8> {
+    let mut full_contents = Vec::with_capacity(4 + contents.len());
+    full_contents.push(version);
+    full_contents.extend_from_slice(&flags.to_be_bytes()[1..4]);
+    full_contents.extend_from_slice(contents);
+    write_box(fourcc, &full_contents)
+}
+
+/// scheme_id_uri identifying an `emsg` box as carrying BPM metrics.
+const BPM_EMSG_SCHEME_ID_URI: &str = "urn:bpm";
+
+/// Package a BPM metrics payload (UUID + counters, as returned by `bpm_sm`/`bpm_erm`)
+/// into a version-1 `emsg` box, so a packager can interleave BPM metrics with the
+/// video `moof`/`mdat` fragments it already produces, by splicing the box ahead of
+/// the media fragment it describes.
+pub fn bpm_emsg(payload: &[u8], timescale: u32, presentation_time: u64, event_duration: u32, id: u32) -> Vec<u8> {
+    let mut contents = Vec::with_capacity(24 + BPM_EMSG_SCHEME_ID_URI.len() + 2 + payload.len());
+    contents.extend_from_slice(&timescale.to_be_bytes());
+    contents.extend_from_slice(&presentation_time.to_be_bytes());
+    contents.extend_from_slice(&event_duration.to_be_bytes());
+    contents.extend_from_slice(&id.to_be_bytes());
+    contents.extend_from_slice(BPM_EMSG_SCHEME_ID_URI.as_bytes());
+    contents.push(0x00); // scheme_id_uri terminator
+    contents.push(0x00); // value (unused): terminator only
+    contents.extend_from_slice(payload);
+
+    write_full_box(b"emsg", 1, 0, &contents)
+}